@@ -0,0 +1,68 @@
+use clap::{Parser, Subcommand};
+use kvs::{Command, Error, Response, Result};
+use std::{io::BufReader, net::TcpStream, process::exit};
+
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: ClientCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum ClientCommand {
+    #[command(arg_required_else_help = true)]
+    Get {
+        key: String,
+        #[arg(long, default_value = kvs::DEFAULT_ADDR)]
+        addr: String,
+    },
+
+    #[command(arg_required_else_help = true)]
+    Set {
+        key: String,
+        value: String,
+        #[arg(long, default_value = kvs::DEFAULT_ADDR)]
+        addr: String,
+    },
+
+    #[command(arg_required_else_help = true)]
+    Rm {
+        key: String,
+        #[arg(long, default_value = kvs::DEFAULT_ADDR)]
+        addr: String,
+    },
+}
+
+/// Send one command to the server at `addr` and read back its response.
+fn request(addr: &str, command: Command) -> Result<Response> {
+    let stream = TcpStream::connect(addr).map_err(Error::Network)?;
+    kvs::write_frame(&stream, &command)?;
+    kvs::read_frame(BufReader::new(&stream))
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        ClientCommand::Get { key, addr } => match request(&addr, Command::Get { key })? {
+            Response::Ok(Some(value)) => println!("{value}"),
+            Response::Ok(None) => println!("Key not found"),
+            Response::Err(msg) => return Err(Error::Server(msg)),
+        },
+        ClientCommand::Set { key, value, addr } => {
+            match request(&addr, Command::Set { key, value })? {
+                Response::Ok(_) => {}
+                Response::Err(msg) => return Err(Error::Server(msg)),
+            }
+        }
+        ClientCommand::Rm { key, addr } => match request(&addr, Command::Remove { key })? {
+            Response::Ok(_) => {}
+            // Mirror the embedded CLI: a missing key prints the message and exits 1.
+            Response::Err(ref msg) if *msg == Error::KeyNotFound.to_string() => {
+                println!("Key not found");
+                exit(1);
+            }
+            Response::Err(msg) => return Err(Error::Server(msg)),
+        },
+    };
+    Ok(())
+}
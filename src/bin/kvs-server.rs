@@ -0,0 +1,53 @@
+use clap::Parser;
+use kvs::{Command, KvsEngine, Response, Result};
+use std::{
+    env::current_dir,
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+};
+
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Address to listen on.
+    #[arg(long, default_value = kvs::DEFAULT_ADDR)]
+    addr: String,
+
+    /// Storage engine to use: "kvs" or "sled". Defaults to the engine recorded for the data
+    /// directory, or "kvs" for a fresh one.
+    #[arg(long, value_parser = ["kvs", "sled"])]
+    engine: Option<String>,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let mut engine = kvs::open_engine(current_dir()?, cli.engine.as_deref())?;
+
+    let listener = TcpListener::bind(&cli.addr)?;
+    for stream in listener.incoming() {
+        // A single misbehaving client (e.g. one that drops the connection mid-frame) must not take
+        // the whole server down: log the failure and keep serving other connections.
+        let result = stream
+            .map_err(Into::into)
+            .and_then(|stream| serve(engine.as_mut(), stream));
+        if let Err(e) = result {
+            eprintln!("connection error: {e}");
+        }
+    }
+    Ok(())
+}
+
+/// Read one request frame, run it against the engine, and write back a single response frame.
+fn serve(engine: &mut dyn KvsEngine, stream: TcpStream) -> Result<()> {
+    let command: Command = kvs::read_frame(BufReader::new(&stream))?;
+    let result = match command {
+        Command::Set { key, value } => engine.set(key, value).map(|_| None),
+        Command::Get { key } => engine.get(key),
+        Command::Remove { key } => engine.remove(key).map(|_| None),
+    };
+    let response = match result {
+        Ok(value) => Response::Ok(value),
+        Err(e) => Response::Err(e.to_string()),
+    };
+    kvs::write_frame(&stream, &response)
+}
@@ -1,10 +1,21 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use kvs::{Error, KvStore, Result};
 use std::{env::current_dir, process::exit};
 
 #[derive(Debug, Parser)]
-#[command(author, version, about, long_about=None)]
-enum Args {
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Storage engine to use: "kvs" (the built-in log-structured store) or "sled". Defaults to the
+    /// engine recorded for the data directory, or "kvs" for a fresh one.
+    #[arg(long, value_parser = ["kvs", "sled"])]
+    engine: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
     #[command(arg_required_else_help = true)]
     Get { key: String },
 
@@ -13,24 +24,28 @@ enum Args {
 
     #[command(arg_required_else_help = true)]
     Rm { key: String },
+
+    /// Migrate a headerless or older-version log in the current directory to the latest format.
+    Upgrade,
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
-    match args {
-        Args::Get { key } => {
-            let mut store = KvStore::open(current_dir()?)?;
+    let cli = Cli::parse();
+    let engine = cli.engine.as_deref();
+    match cli.command {
+        Command::Get { key } => {
+            let mut store = kvs::open_engine(current_dir()?, engine)?;
             match store.get(key.clone())? {
                 Some(v) => println!("{v}"),
                 None => println!("Key not found"),
             }
         }
-        Args::Set { key, value } => {
-            let mut store = KvStore::open(current_dir()?)?;
+        Command::Set { key, value } => {
+            let mut store = kvs::open_engine(current_dir()?, engine)?;
             store.set(key, value)?;
         }
-        Args::Rm { key } => {
-            let mut store = KvStore::open(current_dir()?)?;
+        Command::Rm { key } => {
+            let mut store = kvs::open_engine(current_dir()?, engine)?;
             match store.remove(key) {
                 Ok(_) => {}
                 Err(Error::KeyNotFound) => {
@@ -40,6 +55,9 @@ fn main() -> Result<()> {
                 Err(e) => return Err(e),
             };
         }
+        Command::Upgrade => {
+            KvStore::upgrade(current_dir()?)?;
+        }
     };
     Ok(())
 }